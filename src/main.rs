@@ -23,10 +23,150 @@
 */
 
 use anyhow::{anyhow, Result};
-use bitvec::prelude as bv;
 use csv::{ReaderBuilder, Trim};
-use serde::{Deserialize, Serialize};
-use std::{collections::HashMap, env, fmt, mem, path::Path};
+use serde::{de, Deserialize, Deserializer, Serialize};
+use std::{
+    collections::{BTreeMap, HashMap},
+    env, fmt,
+    io::Write,
+    iter::Sum,
+    mem,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    path::Path,
+};
+
+// Fixed-point money type: stores value as ten-thousandths of a unit so that
+// the "up to 4 decimal places" the spec requires round-trips exactly instead
+// of accumulating f32 binary-rounding error.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+struct Amount(i64);
+
+const AMOUNT_SCALE: i64 = 10_000;
+
+impl Amount {
+    const ZERO: Amount = Amount(0);
+
+    fn from_scaled(scaled: i64) -> Self {
+        Amount(scaled)
+    }
+}
+
+impl Add for Amount {
+    type Output = Amount;
+    fn add(self, rhs: Amount) -> Amount {
+        Amount(self.0 + rhs.0)
+    }
+}
+
+impl Sub for Amount {
+    type Output = Amount;
+    fn sub(self, rhs: Amount) -> Amount {
+        Amount(self.0 - rhs.0)
+    }
+}
+
+impl AddAssign for Amount {
+    fn add_assign(&mut self, rhs: Amount) {
+        self.0 += rhs.0;
+    }
+}
+
+impl SubAssign for Amount {
+    fn sub_assign(&mut self, rhs: Amount) {
+        self.0 -= rhs.0;
+    }
+}
+
+impl Sum for Amount {
+    fn sum<I: Iterator<Item = Amount>>(iter: I) -> Amount {
+        iter.fold(Amount::ZERO, |acc, amount| acc + amount)
+    }
+}
+
+impl fmt::Display for Amount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let negative = self.0 < 0;
+        let magnitude = self.0.unsigned_abs();
+        let whole = magnitude / AMOUNT_SCALE as u64;
+        let frac = magnitude % AMOUNT_SCALE as u64;
+        if negative {
+            write!(f, "-")?;
+        }
+        if frac == 0 {
+            write!(f, "{}", whole)
+        } else {
+            let frac_str = format!("{:04}", frac);
+            write!(f, "{}.{}", whole, frac_str.trim_end_matches('0'))
+        }
+    }
+}
+
+// Human-readable formats (CSV) encode the amount as the decimal string a user
+// would type; binary formats (bincode, for the sled-backed store) just carry
+// the scaled i64 directly.
+impl Serialize for Amount {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        if serializer.is_human_readable() {
+            serializer.collect_str(self)
+        } else {
+            serializer.serialize_i64(self.0)
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Amount {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        if deserializer.is_human_readable() {
+            let raw = String::deserialize(deserializer)?;
+            parse_amount(&raw).map_err(de::Error::custom)
+        } else {
+            i64::deserialize(deserializer).map(Amount::from_scaled)
+        }
+    }
+}
+
+// Parses a decimal string (e.g. "2.742") into ten-thousandths, padding the
+// fractional part with trailing zeros to exactly 4 digits and rejecting
+// inputs that specify more precision than that (nothing is truncated).
+fn parse_amount(raw: &str) -> std::result::Result<Amount, String> {
+    let raw = raw.trim();
+    let (sign, raw) = match raw.strip_prefix('-') {
+        Some(rest) => (-1i64, rest),
+        None => (1i64, raw),
+    };
+    let mut parts = raw.splitn(2, '.');
+    let whole_part = parts.next().unwrap_or("");
+    let frac_part = parts.next().unwrap_or("");
+    if frac_part.len() > 4 {
+        return Err(format!(
+            "amount {:?} has more than 4 decimal places",
+            raw
+        ));
+    }
+    let whole: i64 = if whole_part.is_empty() {
+        0
+    } else {
+        whole_part
+            .parse()
+            .map_err(|_| format!("invalid amount {:?}", raw))?
+    };
+    let padded_frac = format!("{:0<4}", frac_part);
+    let frac: i64 = padded_frac
+        .parse()
+        .map_err(|_| format!("invalid amount {:?}", raw))?;
+    let scaled = whole
+        .checked_mul(AMOUNT_SCALE)
+        .and_then(|whole_scaled| whole_scaled.checked_add(frac))
+        .and_then(|magnitude| magnitude.checked_mul(sign))
+        .ok_or_else(|| format!("amount {:?} is out of range", raw))?;
+    Ok(Amount::from_scaled(scaled))
+}
 
 // Serde deserialization helper
 #[derive(Clone, Debug, Deserialize)]
@@ -47,313 +187,417 @@ struct RawRecord {
     client: u16, // unique client id
     #[serde(rename = "tx")]
     transaction: u32, // globally unique transaction id
-    amount: f32, // amount of money with up to 4 decimal places
+    // dispute/resolve/chargeback rows write this as an empty field, and some
+    // files omit the trailing column entirely (hence `.flexible(true)` on the
+    // reader), so it has to tolerate being absent.
+    #[serde(default)]
+    amount: Option<Amount>,
 }
 
 // A single transaction that holds the transaction id and the amount of money
 // that was transferred depending on the transaction type.
 #[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
 enum TransactionOp {
-    Deposit(u32, f32),
-    Withdraw(u32, f32),
+    Deposit(u32, Amount),
+    Withdraw(u32, Amount),
     Dispute(u32),    // transaction id
     Resolve(u32),    // transaction_id
     Chargeback(u32), // transaction_id
 }
 
-impl From<RawRecord> for TransactionOp {
-    fn from(record: RawRecord) -> Self {
-        match record.transaction_type {
-            TransactionType::Deposit => TransactionOp::Deposit(record.transaction, record.amount),
-            TransactionType::Withdrawal => {
-                TransactionOp::Withdraw(record.transaction, record.amount)
+// All the ways a single transaction can be legitimately rejected, whether at
+// parse time or while applying it to an account.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum LedgerError {
+    InsufficientFunds,
+    AccountLocked,
+    UnknownTx,
+    AlreadyDisputed,
+    NotDisputed,
+    MissingAmount,
+    // The tx id exists, but the global tx-owner index records it against a
+    // different client.
+    ClientMismatch,
+}
+
+impl fmt::Display for LedgerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LedgerError::InsufficientFunds => write!(f, "insufficient available funds"),
+            LedgerError::AccountLocked => write!(f, "account is locked"),
+            LedgerError::UnknownTx => write!(f, "unknown transaction id"),
+            LedgerError::AlreadyDisputed => write!(f, "transaction is already disputed"),
+            LedgerError::NotDisputed => write!(f, "transaction is not under dispute"),
+            LedgerError::MissingAmount => {
+                write!(f, "deposit/withdrawal record is missing an amount")
             }
-            TransactionType::Dispute => TransactionOp::Dispute(record.transaction),
-            TransactionType::Resolve => TransactionOp::Resolve(record.transaction),
-            TransactionType::Chargeback => TransactionOp::Chargeback(record.transaction),
+            LedgerError::ClientMismatch => write!(f, "transaction does not belong to this client"),
         }
     }
 }
 
-impl From<&RawRecord> for TransactionOp {
-    fn from(record: &RawRecord) -> Self {
+impl std::error::Error for LedgerError {}
+
+impl TryFrom<&RawRecord> for TransactionOp {
+    type Error = LedgerError;
+
+    fn try_from(record: &RawRecord) -> Result<Self, Self::Error> {
         match record.transaction_type {
-            TransactionType::Deposit => TransactionOp::Deposit(record.transaction, record.amount),
-            TransactionType::Withdrawal => {
-                TransactionOp::Withdraw(record.transaction, record.amount)
-            }
-            TransactionType::Dispute => TransactionOp::Dispute(record.transaction),
-            TransactionType::Resolve => TransactionOp::Resolve(record.transaction),
-            TransactionType::Chargeback => TransactionOp::Chargeback(record.transaction),
+            TransactionType::Deposit => Ok(TransactionOp::Deposit(
+                record.transaction,
+                record.amount.ok_or(LedgerError::MissingAmount)?,
+            )),
+            TransactionType::Withdrawal => Ok(TransactionOp::Withdraw(
+                record.transaction,
+                record.amount.ok_or(LedgerError::MissingAmount)?,
+            )),
+            TransactionType::Dispute => Ok(TransactionOp::Dispute(record.transaction)),
+            TransactionType::Resolve => Ok(TransactionOp::Resolve(record.transaction)),
+            TransactionType::Chargeback => Ok(TransactionOp::Chargeback(record.transaction)),
         }
     }
 }
 
-#[derive(Debug, Deserialize, Serialize)]
-struct Account {
-    id: u16,
-    available_funds: f32,
-    held_funds: f32,
-    total_funds: f32,
+// Tracks the dispute lifecycle of a single deposit/withdrawal so that
+// resolve/chargeback can't fire against a transaction that was never
+// disputed, and a transaction can't be disputed twice.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+enum TxState {
+    Processed,
+    Disputed,
+    Resolved,
+    ChargedBack,
+}
+
+// Everything a dispute/resolve/chargeback needs to know about a single
+// deposit or withdrawal, keyed by `(client, tx)` instead of living inside a
+// growing per-account log. Looking this up is O(1) regardless of how many
+// transactions the client has made.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+struct TxRecord {
+    amount: Amount,
+    state: TxState,
+}
+
+// The running balance for one client. `total` isn't stored separately: it's
+// always `available + held`, since every op that moves funds into or out of
+// `held` balances the books by moving the same amount out of or into
+// `available` (or, for a chargeback, out of `held` with nothing reciprocal,
+// which is exactly why a chargeback also reduces the total).
+#[derive(Clone, Copy, Debug, Default, Serialize, Deserialize)]
+struct AccountInfo {
+    available: Amount,
+    held: Amount,
     locked: bool,
-    in_dispute: bool, // if true, the account is in dispute and the CSV file needs to be replayed to resolve it
-    last_processed_transaction: u32,
-    transaction_log: Vec<TransactionOp>, // Either all transactions or a subset of transactions of one_pass is false
 }
 
-impl fmt::Display for Account {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(
-            f,
-            "{}, {}, {}, {}, {}",
-            self.id, self.available_funds, self.held_funds, self.total_funds, self.locked
-        )
+impl AccountInfo {
+    fn total(&self) -> Amount {
+        self.available + self.held
     }
 }
 
+#[cfg(test)]
+fn amt(raw: &str) -> Amount {
+    parse_amount(raw).unwrap()
+}
+
 #[test]
 fn test_record_processor() {
     //
 }
 
+#[cfg(test)]
+fn apply_ops(store: &mut MemStore, client: u16, ops: &[TransactionOp]) {
+    for op in ops {
+        apply_transaction(store, client, op).unwrap();
+    }
+}
+
 #[test]
 fn test_process_account() {
-    // Create some sample account transactions and process them
-
     // First test an easy case
-    let mut account = Account {
-        id: 1,
-        available_funds: 0.0,
-        held_funds: 0.0,
-        total_funds: 0.0,
-        locked: false,
-        in_dispute: false,
-        last_processed_transaction: 0,
-        transaction_log: vec![
-            TransactionOp::Deposit(1, 100.0000),
-            TransactionOp::Withdraw(2, 50.0000),
-            TransactionOp::Withdraw(3, 25.0000),
+    let mut store = MemStore::new();
+    apply_ops(
+        &mut store,
+        1,
+        &[
+            TransactionOp::Deposit(1, amt("100.0000")),
+            TransactionOp::Withdraw(2, amt("50.0000")),
+            TransactionOp::Withdraw(3, amt("25.0000")),
         ],
-    };
-    process_account(&mut account);
-    assert_eq!(account.available_funds, 25.0000);
-    assert_eq!(account.held_funds, 0.0);
-    assert_eq!(account.total_funds, 25.0000);
-    assert_eq!(account.locked, false);
-    assert_eq!(account.in_dispute, false);
-    assert_eq!(account.last_processed_transaction, 3);
-    assert_eq!(account.transaction_log.len(), 0);
+    );
+    let account = store.get_account(1).unwrap().unwrap();
+    assert_eq!(account.available, amt("25.0000"));
+    assert_eq!(account.held, Amount::ZERO);
+    assert_eq!(account.total(), amt("25.0000"));
+    assert!(!account.locked);
 }
 
 #[test]
 fn test_process_dispute() {
     // Now test a more complex case
-    let mut account = Account {
-        id: 1,
-        available_funds: 0.0,
-        held_funds: 0.0,
-        total_funds: 0.0,
-        locked: false,
-        in_dispute: false,
-        last_processed_transaction: 0,
-        transaction_log: vec![
-            TransactionOp::Deposit(1, 100.0000),
-            TransactionOp::Deposit(2, 100.0000),
-            TransactionOp::Withdraw(3, 50.0000),
+    let mut store = MemStore::new();
+    apply_ops(
+        &mut store,
+        1,
+        &[
+            TransactionOp::Deposit(1, amt("100.0000")),
+            TransactionOp::Deposit(2, amt("100.0000")),
+            TransactionOp::Withdraw(3, amt("50.0000")),
             TransactionOp::Dispute(2),
         ],
-    };
-    process_account(&mut account);
-    assert_eq!(account.available_funds, 50.0000);
-    assert_eq!(account.held_funds, 100.0000);
-    assert_eq!(account.total_funds, 150.0000);
+    );
+    let account = store.get_account(1).unwrap().unwrap();
+    assert_eq!(account.available, amt("50.0000"));
+    assert_eq!(account.held, amt("100.0000"));
+    assert_eq!(account.total(), amt("150.0000"));
 }
 
 #[test]
 fn test_process_chargeback() {
-    let mut account = Account {
-        id: 1,
-        available_funds: 0.0,
-        held_funds: 0.0,
-        total_funds: 0.0,
-        locked: false,
-        in_dispute: false,
-        last_processed_transaction: 0,
-        transaction_log: vec![
-            TransactionOp::Deposit(1, 100.0000),
-            TransactionOp::Deposit(2, 100.0000),
-            TransactionOp::Withdraw(3, 50.0000),
+    let mut store = MemStore::new();
+    apply_ops(
+        &mut store,
+        1,
+        &[
+            TransactionOp::Deposit(1, amt("100.0000")),
+            TransactionOp::Deposit(2, amt("100.0000")),
+            TransactionOp::Withdraw(3, amt("50.0000")),
             TransactionOp::Dispute(2),
             TransactionOp::Chargeback(2),
         ],
-    };
-    process_account(&mut account);
-    assert_eq!(account.available_funds, 50.0000);
-    assert_eq!(account.held_funds, 0.0000);
-    assert_eq!(account.total_funds, 50.0000);
-    assert_eq!(account.locked, true);
+    );
+    let account = store.get_account(1).unwrap().unwrap();
+    assert_eq!(account.available, amt("50.0000"));
+    assert_eq!(account.held, Amount::ZERO);
+    assert_eq!(account.total(), amt("50.0000"));
+    assert!(account.locked);
 }
 
 #[test]
 fn test_process_resolve() {
-    let mut account = Account {
-        id: 1,
-        available_funds: 0.0,
-        held_funds: 0.0,
-        total_funds: 0.0,
-        locked: false,
-        in_dispute: false,
-        last_processed_transaction: 0,
-        transaction_log: vec![
-            TransactionOp::Deposit(1, 100.0000),
-            TransactionOp::Deposit(2, 100.0000),
-            TransactionOp::Withdraw(3, 50.0000),
+    let mut store = MemStore::new();
+    apply_ops(
+        &mut store,
+        1,
+        &[
+            TransactionOp::Deposit(1, amt("100.0000")),
+            TransactionOp::Deposit(2, amt("100.0000")),
+            TransactionOp::Withdraw(3, amt("50.0000")),
             TransactionOp::Dispute(2),
             TransactionOp::Resolve(2),
         ],
+    );
+    let account = store.get_account(1).unwrap().unwrap();
+    assert_eq!(account.available, amt("150.0000"));
+    assert_eq!(account.held, Amount::ZERO);
+    assert_eq!(account.total(), amt("150.0000"));
+    assert!(!account.locked);
+}
+
+#[cfg(test)]
+fn ledger_err(result: Result<()>) -> LedgerError {
+    *result.unwrap_err().downcast_ref::<LedgerError>().unwrap()
+}
+
+#[test]
+fn test_withdraw_overdraws_rejected() {
+    let mut store = MemStore::new();
+    apply_ops(&mut store, 1, &[TransactionOp::Deposit(1, amt("10.0000"))]);
+    let err = ledger_err(apply_transaction(
+        &mut store,
+        1,
+        &TransactionOp::Withdraw(2, amt("20.0000")),
+    ));
+    assert_eq!(err, LedgerError::InsufficientFunds);
+}
+
+#[test]
+fn test_locked_account_rejects_everything() {
+    let mut store = MemStore::new();
+    apply_ops(
+        &mut store,
+        1,
+        &[
+            TransactionOp::Deposit(1, amt("10.0000")),
+            TransactionOp::Dispute(1),
+            TransactionOp::Chargeback(1),
+        ],
+    );
+    let err = ledger_err(apply_transaction(
+        &mut store,
+        1,
+        &TransactionOp::Deposit(2, amt("10.0000")),
+    ));
+    assert_eq!(err, LedgerError::AccountLocked);
+}
+
+#[test]
+fn test_dispute_unknown_tx_rejected() {
+    let mut store = MemStore::new();
+    let err = ledger_err(apply_transaction(&mut store, 1, &TransactionOp::Dispute(1)));
+    assert_eq!(err, LedgerError::UnknownTx);
+}
+
+#[test]
+fn test_dispute_wrong_client_rejected() {
+    let mut store = MemStore::new();
+    apply_ops(&mut store, 1, &[TransactionOp::Deposit(1, amt("10.0000"))]);
+    let err = ledger_err(apply_transaction(&mut store, 2, &TransactionOp::Dispute(1)));
+    assert_eq!(err, LedgerError::ClientMismatch);
+}
+
+#[test]
+fn test_dispute_already_disputed_rejected() {
+    let mut store = MemStore::new();
+    apply_ops(
+        &mut store,
+        1,
+        &[
+            TransactionOp::Deposit(1, amt("10.0000")),
+            TransactionOp::Dispute(1),
+        ],
+    );
+    let err = ledger_err(apply_transaction(&mut store, 1, &TransactionOp::Dispute(1)));
+    assert_eq!(err, LedgerError::AlreadyDisputed);
+}
+
+#[test]
+fn test_resolve_without_dispute_rejected() {
+    let mut store = MemStore::new();
+    apply_ops(&mut store, 1, &[TransactionOp::Deposit(1, amt("10.0000"))]);
+    let err = ledger_err(apply_transaction(&mut store, 1, &TransactionOp::Resolve(1)));
+    assert_eq!(err, LedgerError::NotDisputed);
+}
+
+#[test]
+fn test_chargeback_without_dispute_rejected() {
+    let mut store = MemStore::new();
+    apply_ops(&mut store, 1, &[TransactionOp::Deposit(1, amt("10.0000"))]);
+    let err = ledger_err(apply_transaction(&mut store, 1, &TransactionOp::Chargeback(1)));
+    assert_eq!(err, LedgerError::NotDisputed);
+}
+
+#[test]
+fn test_missing_amount_rejected() {
+    let record = RawRecord {
+        transaction_type: TransactionType::Deposit,
+        client: 1,
+        transaction: 1,
+        amount: None,
     };
-    process_account(&mut account);
-    assert_eq!(account.available_funds, 150.0000);
-    assert_eq!(account.held_funds, 0.0000);
-    assert_eq!(account.total_funds, 150.0000);
-    assert_eq!(account.locked, false);
-}
-
-// Process a transaction and update the account balance
-fn process_account(account: &mut Account) {
-    for transaction in &account.transaction_log {
-        // Walk through the transactions in order and process them
-        match transaction {
-            TransactionOp::Deposit(tx_id, amount) => {
-                account.available_funds += amount;
-                account.total_funds += amount;
-                account.last_processed_transaction = *tx_id;
-            }
-            TransactionOp::Withdraw(tx_id, amount) => {
-                account.available_funds -= amount;
-                account.total_funds -= amount;
-                account.last_processed_transaction = *tx_id;
+    let err = TransactionOp::try_from(&record).unwrap_err();
+    assert_eq!(err, LedgerError::MissingAmount);
+}
+
+// Applies a single transaction directly against `store`, gated by the tx
+// state machine, returning the specific LedgerError rather than silently
+// corrupting balances when the transaction is illegal. Unlike the old
+// log-and-replay model, this looks up the original deposit/withdrawal amount
+// in O(1) instead of rescanning the client's whole history on every
+// dispute/resolve/chargeback.
+fn apply_transaction<S: Store>(store: &mut S, client: u16, transaction: &TransactionOp) -> Result<()> {
+    let mut account = store.get_account(client)?.unwrap_or_default();
+    if account.locked {
+        return Err(LedgerError::AccountLocked.into());
+    }
+    match transaction {
+        TransactionOp::Deposit(tx_id, amount) => {
+            account.available += *amount;
+            store.upsert_tx(
+                client,
+                *tx_id,
+                TxRecord {
+                    amount: *amount,
+                    state: TxState::Processed,
+                },
+            )?;
+            store.set_tx_owner(*tx_id, client)?;
+        }
+        TransactionOp::Withdraw(tx_id, amount) => {
+            if *amount > account.available {
+                return Err(LedgerError::InsufficientFunds.into());
             }
-            TransactionOp::Dispute(tx_id) => {
-                // Find the transaction in the log
-                let dispute_op = match find_transaction(*tx_id, &account.transaction_log) {
-                    Some(dispute_op) => dispute_op,
-                    None => {
-                        // if the tx specified doesn't exist, or the tx isn't under dispute, ignore it
-                        return;
-                    }
-                };
-                let amount = match find_transaction_amount(dispute_op) {
-                    Some(amount) => amount,
-                    None => {
-                        // if the tx specified doesn't exist, or the tx isn't under dispute, ignore it
-                        return;
-                    }
-                };
-                account.in_dispute = true;
-                account.available_funds -= amount;
-                account.held_funds += amount;
-                // Total funds should remain the same
-                account.last_processed_transaction = *tx_id;
+            account.available -= *amount;
+            store.upsert_tx(
+                client,
+                *tx_id,
+                TxRecord {
+                    amount: *amount,
+                    state: TxState::Processed,
+                },
+            )?;
+            store.set_tx_owner(*tx_id, client)?;
+        }
+        TransactionOp::Dispute(tx_id) => {
+            let mut record = match store.get_tx(client, *tx_id)? {
+                Some(record) => record,
+                None => return Err(unknown_tx_error(store, client, *tx_id)?.into()),
+            };
+            if record.state != TxState::Processed {
+                return Err(LedgerError::AlreadyDisputed.into());
             }
-            TransactionOp::Resolve(tx_id) => {
-                let resolve_op = match find_transaction(*tx_id, &account.transaction_log) {
-                    Some(resolve_op) => resolve_op,
-                    None => {
-                        // if the tx specified doesn't exist, or the tx isn't under dispute, ignore it
-                        return;
-                    }
-                };
-                let amount = match find_transaction_amount(resolve_op) {
-                    Some(amount) => amount,
-                    None => {
-                        // if the tx specified doesn't exist, or the tx isn't under dispute, ignore it
-                        return;
-                    }
-                };
-                account.held_funds -= amount;
-                account.available_funds += amount;
+            account.available -= record.amount;
+            account.held += record.amount;
+            record.state = TxState::Disputed;
+            store.upsert_tx(client, *tx_id, record)?;
+        }
+        TransactionOp::Resolve(tx_id) => {
+            let mut record = match store.get_tx(client, *tx_id)? {
+                Some(record) => record,
+                None => return Err(unknown_tx_error(store, client, *tx_id)?.into()),
+            };
+            if record.state != TxState::Disputed {
+                return Err(LedgerError::NotDisputed.into());
             }
-            TransactionOp::Chargeback(tx_id) => {
-                let chargeback_op = match find_transaction(*tx_id, &account.transaction_log) {
-                    Some(chargeback_op) => chargeback_op,
-                    None => {
-                        // if the tx specified doesn't exist, or the tx isn't under dispute, ignore it
-                        return;
-                    }
-                };
-                let amount = match find_transaction_amount(chargeback_op) {
-                    Some(amount) => amount,
-                    None => {
-                        // if the tx specified doesn't exist, or the tx isn't under dispute, ignore it
-                        return;
-                    }
-                };
-                account.held_funds -= amount;
-                account.total_funds -= amount;
-                account.locked = true;
-                account.last_processed_transaction = *tx_id;
+            account.held -= record.amount;
+            account.available += record.amount;
+            record.state = TxState::Resolved;
+            store.upsert_tx(client, *tx_id, record)?;
+        }
+        TransactionOp::Chargeback(tx_id) => {
+            let mut record = match store.get_tx(client, *tx_id)? {
+                Some(record) => record,
+                None => return Err(unknown_tx_error(store, client, *tx_id)?.into()),
+            };
+            if record.state != TxState::Disputed {
+                return Err(LedgerError::NotDisputed.into());
             }
+            account.held -= record.amount;
+            account.locked = true;
+            record.state = TxState::ChargedBack;
+            store.upsert_tx(client, *tx_id, record)?;
         }
     }
-
-    account.transaction_log.clear();
-}
-
-// Builds up a list of transactions in memory.
-fn store_record(record: &RawRecord, accounts: &mut HashMap<u16, Account>) {
-    accounts
-        .entry(record.client)
-        .and_modify(|account| {
-            account.transaction_log.push(record.into());
-        })
-        .or_insert(Account {
-            id: record.client,
-            available_funds: 0.0,
-            held_funds: 0.0,
-            total_funds: 0.0,
-            locked: false,
-            last_processed_transaction: 0,
-            in_dispute: false,
-            transaction_log: vec![record.into()],
-        });
+    store.upsert_account(client, account)?;
+    Ok(())
 }
 
-fn find_transaction_amount(transaction_op: &TransactionOp) -> Option<f32> {
-    match transaction_op {
-        TransactionOp::Deposit(_, amount) => Some(*amount),
-        TransactionOp::Withdraw(_, amount) => Some(*amount),
-        _ => None,
+// Distinguishes a genuinely unknown tx id from one that exists but was
+// recorded against a different client, using the global tx-owner index.
+fn unknown_tx_error<S: Store>(store: &S, client: u16, tx_id: u32) -> Result<LedgerError> {
+    match store.get_tx_owner(tx_id)? {
+        Some(owner) if owner != client => Ok(LedgerError::ClientMismatch),
+        _ => Ok(LedgerError::UnknownTx),
     }
 }
 
-fn find_transaction(id: u32, transaction_log: &[TransactionOp]) -> Option<&TransactionOp> {
-    transaction_log
-        .iter()
-        .find(|transaction| match transaction {
-            TransactionOp::Deposit(tx_id, _) => *tx_id == id,
-            TransactionOp::Withdraw(tx_id, _) => *tx_id == id,
-            TransactionOp::Dispute(tx_id) => *tx_id == id,
-            TransactionOp::Resolve(tx_id) => *tx_id == id,
-            TransactionOp::Chargeback(tx_id) => *tx_id == id,
-        })
-}
-
 // Insert all checks for the server environment into this function
 fn environment_check(csv_file: &Path) -> Result<bool> {
     // Gives a _very_ rough estimate of the line count using worst case scenario of 35 bytes per line
     let csv_file_lines = csv_file.metadata()?.len() / 35;
     let memory_info = sys_info::mem_info()?;
-    println!("memory_info avail: {}", memory_info.avail);
-    let account_memory_size = mem::size_of::<Account>();
+    eprintln!("memory_info avail: {}", memory_info.avail);
+    let account_memory_size = mem::size_of::<AccountInfo>();
 
     // Figure out the number of accounts that can safely fit in memory
     // This is a conservative estimate of the number of accounts that can fit in memory
     // because TransactionOps are much smaller than Accounts. This assumes a worst case scenario of
     // no duplicate accounts
-    println!("account memory size: {}", account_memory_size);
+    eprintln!("account memory size: {}", account_memory_size);
     let max_accounts = memory_info.avail / account_memory_size as u64;
-    println!("max_accounts: {}", max_accounts);
+    eprintln!("max_accounts: {}", max_accounts);
 
     // Find the number of lines in the file
     if csv_file_lines > max_accounts {
@@ -365,82 +609,258 @@ fn environment_check(csv_file: &Path) -> Result<bool> {
     Ok(true)
 }
 
-fn print_accounts(accounts: &mut HashMap<u16, Account>) {
-    print_output_header();
-    for (_, account) in &mut accounts.iter_mut() {
-        process_account(account);
-        println!("{}", account);
+// A single output row; kept separate from `AccountInfo` so the CSV output
+// only ever exposes the fields the spec asks for, not our internal
+// bookkeeping.
+#[derive(Serialize)]
+struct OutputRow {
+    client: u16,
+    available: Amount,
+    held: Amount,
+    total: Amount,
+    locked: bool,
+}
+
+impl OutputRow {
+    fn new(client: u16, account: &AccountInfo) -> Self {
+        OutputRow {
+            client,
+            available: account.available,
+            held: account.held,
+            total: account.total(),
+            locked: account.locked,
+        }
+    }
+}
+
+// Serializes every account as one CSV row, sorted by client id so output is
+// deterministic and diffable across runs.
+fn dump_csv<W: Write>(accounts: &HashMap<u16, AccountInfo>, writer: &mut csv::Writer<W>) -> Result<()> {
+    let sorted: BTreeMap<u16, &AccountInfo> = accounts.iter().map(|(id, account)| (*id, account)).collect();
+    for (client, account) in &sorted {
+        writer.serialize(OutputRow::new(*client, account))?;
     }
+    writer.flush()?;
+    Ok(())
+}
+
+#[test]
+fn test_dump_csv_sorted_and_formatted() {
+    let mut accounts = HashMap::new();
+    accounts.insert(
+        2,
+        AccountInfo {
+            available: amt("5.0000"),
+            held: Amount::ZERO,
+            locked: false,
+        },
+    );
+    accounts.insert(
+        1,
+        AccountInfo {
+            available: amt("1.5000"),
+            held: amt("0.5000"),
+            locked: true,
+        },
+    );
+    let mut writer = csv::WriterBuilder::new().from_writer(Vec::new());
+    dump_csv(&accounts, &mut writer).unwrap();
+    let output = String::from_utf8(writer.into_inner().unwrap()).unwrap();
+    assert_eq!(
+        output,
+        "client,available,held,total,locked\n1,1.5,0.5,2,true\n2,5,0,5,false\n"
+    );
 }
 
-fn print_output_header() {
-    println!("client, available, held, total, locked");
+// Backs the account table and the per-tx reversal records, so the engine can
+// run against either an in-memory HashMap or an out-of-core sled database
+// without duplicating the record-processing loop below.
+trait Store {
+    fn get_account(&self, client: u16) -> Result<Option<AccountInfo>>;
+    fn upsert_account(&mut self, client: u16, account: AccountInfo) -> Result<()>;
+    fn iter_clients(&self) -> Result<Vec<u16>>;
+    fn get_tx(&self, client: u16, tx: u32) -> Result<Option<TxRecord>>;
+    fn upsert_tx(&mut self, client: u16, tx: u32, record: TxRecord) -> Result<()>;
+    // Global tx id -> owning client, independent of the (client, tx) keyed
+    // records above, so a dispute against someone else's tx id can be told
+    // apart from a dispute against a tx id that was never seen at all.
+    fn get_tx_owner(&self, tx: u32) -> Result<Option<u16>>;
+    fn set_tx_owner(&mut self, tx: u32, client: u16) -> Result<()>;
 }
 
-// Takes arguments of one_pass for in memory processing and csv_reader to process
-fn run(one_pass: bool, csv_reader: &mut csv::Reader<std::fs::File>) -> Result<()> {
-    /*
-    if one_pass {
-        let mut accounts = HashMap::new();
-        let record_iter = csv_reader.deserialize();
-        for record in record_iter {
-            let record: RawRecord = record?;
-            store_record(&record, &mut accounts);
+// Everything fits comfortably in working memory.
+struct MemStore {
+    accounts: HashMap<u16, AccountInfo>,
+    tx_records: HashMap<(u16, u32), TxRecord>,
+    tx_owners: HashMap<u32, u16>,
+}
+
+impl MemStore {
+    fn new() -> Self {
+        MemStore {
+            accounts: HashMap::new(),
+            tx_records: HashMap::new(),
+            tx_owners: HashMap::new(),
         }
-        print_accounts(&mut accounts);
-    } else {
-        */
-    // Too large to process in working memory
-    let mut client_accounts: bv::BitArr!(for 65535, in u16) = bv::BitArray::ZERO;
-    let sled_db = sled::Config::new().temporary(true).path("sled.db").open()?;
+    }
+}
+
+impl Store for MemStore {
+    fn get_account(&self, client: u16) -> Result<Option<AccountInfo>> {
+        Ok(self.accounts.get(&client).copied())
+    }
+
+    fn upsert_account(&mut self, client: u16, account: AccountInfo) -> Result<()> {
+        self.accounts.insert(client, account);
+        Ok(())
+    }
+
+    fn iter_clients(&self) -> Result<Vec<u16>> {
+        Ok(self.accounts.keys().copied().collect())
+    }
+
+    fn get_tx(&self, client: u16, tx: u32) -> Result<Option<TxRecord>> {
+        Ok(self.tx_records.get(&(client, tx)).copied())
+    }
+
+    fn upsert_tx(&mut self, client: u16, tx: u32, record: TxRecord) -> Result<()> {
+        self.tx_records.insert((client, tx), record);
+        Ok(())
+    }
+
+    fn get_tx_owner(&self, tx: u32) -> Result<Option<u16>> {
+        Ok(self.tx_owners.get(&tx).copied())
+    }
+
+    fn set_tx_owner(&mut self, tx: u32, client: u16) -> Result<()> {
+        self.tx_owners.insert(tx, client);
+        Ok(())
+    }
+}
+
+// Too large to process in working memory; accounts and tx records are paged
+// through sled and (de)serialized with bincode. Keeping them in separate
+// trees means a dispute only ever rewrites a tiny `TxRecord` entry and the
+// client's `AccountInfo`, never a growing per-account blob.
+struct SledStore {
+    accounts: sled::Tree,
+    tx_records: sled::Tree,
+    tx_owners: sled::Tree,
+}
+
+impl SledStore {
+    fn new(db: &sled::Db) -> Result<Self> {
+        Ok(SledStore {
+            accounts: db.open_tree("accounts")?,
+            tx_records: db.open_tree("tx_records")?,
+            tx_owners: db.open_tree("tx_owners")?,
+        })
+    }
+}
+
+impl Store for SledStore {
+    fn get_account(&self, client: u16) -> Result<Option<AccountInfo>> {
+        match self.accounts.get(client.to_string())? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn upsert_account(&mut self, client: u16, account: AccountInfo) -> Result<()> {
+        self.accounts
+            .insert(client.to_string(), bincode::serialize(&account)?)?;
+        Ok(())
+    }
+
+    fn iter_clients(&self) -> Result<Vec<u16>> {
+        self.accounts
+            .iter()
+            .keys()
+            .map(|key| {
+                let key = key?;
+                Ok(std::str::from_utf8(&key)?.parse::<u16>()?)
+            })
+            .collect()
+    }
+
+    fn get_tx(&self, client: u16, tx: u32) -> Result<Option<TxRecord>> {
+        match self.tx_records.get(format!("{}:{}", client, tx))? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn upsert_tx(&mut self, client: u16, tx: u32, record: TxRecord) -> Result<()> {
+        self.tx_records
+            .insert(format!("{}:{}", client, tx), bincode::serialize(&record)?)?;
+        Ok(())
+    }
+
+    fn get_tx_owner(&self, tx: u32) -> Result<Option<u16>> {
+        match self.tx_owners.get(tx.to_string())? {
+            Some(bytes) => Ok(Some(bincode::deserialize(&bytes)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn set_tx_owner(&mut self, tx: u32, client: u16) -> Result<()> {
+        self.tx_owners.insert(tx.to_string(), bincode::serialize(&client)?)?;
+        Ok(())
+    }
+}
+
+// Reads every record and applies it to `store` as it's read (no per-account
+// log buffered for a later pass), then dumps every account seen. Generic
+// over `Store` so the same loop drives both the in-memory and sled-backed
+// paths.
+fn run<S: Store, W: Write>(
+    store: &mut S,
+    csv_reader: &mut csv::Reader<std::fs::File>,
+    out: W,
+) -> Result<()> {
+    let mut csv_writer = csv::WriterBuilder::new().from_writer(out);
     let record_iter = csv_reader.deserialize();
-    // Walk over the records and store them in the sled database
     let mut line_number = 0;
     for record in record_iter {
-        let record: RawRecord = record?;
-        let client_id = record.client;
-        let mut account = match sled_db.get(record.client.to_string())? {
-            Some(account) => bincode::deserialize::<Account>(&account)?,
-            None => Account {
-                id: record.client,
-                available_funds: 0.0,
-                held_funds: 0.0,
-                total_funds: 0.0,
-                locked: false,
-                last_processed_transaction: 0,
-                in_dispute: false,
-                transaction_log: Vec::new(),
-            },
-        };
-        // Store each transaction in the account
         line_number += 1;
-        println!(
-            "Saving line: {} account: {} and transaction: {:?}",
-            line_number,
-            account,
-            TransactionOp::from(&record)
-        );
-        account.transaction_log.push(record.into());
-        sled_db.insert(client_id.to_string(), bincode::serialize(&account)?)?;
-        // Save the client ID for later
-        client_accounts.set(client_id.into(), true);
-    }
-    println!("processing finished");
-    print_output_header();
-    // For each client process the account and print it out
-    for client in client_accounts.iter_ones() {
-        let mut account: Account = match sled_db.get(client.to_string())? {
-            Some(account) => bincode::deserialize::<Account>(&account)?,
-            None => {
-                // If the account doesn't exist, ignore it
-                eprint!("Account {} doesn't exist but should", client);
+        // A single malformed record shouldn't abort the whole file; log it
+        // and keep processing the rest.
+        let record: RawRecord = match record {
+            Ok(record) => record,
+            Err(err) => {
+                eprintln!("line {}: skipping malformed record: {}", line_number, err);
+                continue;
+            }
+        };
+        let op = match TransactionOp::try_from(&record) {
+            Ok(op) => op,
+            Err(err) => {
+                eprintln!("line {}: skipping record: {}", line_number, err);
                 continue;
             }
         };
-        process_account(&mut account);
-        println!("{}", account);
+        eprintln!("line {}: applying client {} transaction {:?}", line_number, record.client, op);
+        if let Err(err) = apply_transaction(store, record.client, &op) {
+            eprintln!(
+                "line {}: client {}: rejecting transaction {:?}: {}",
+                line_number, record.client, op, err
+            );
+        }
     }
-    //}
+    eprintln!("processing finished");
+    let mut output_accounts = HashMap::new();
+    for client in store.iter_clients()? {
+        match store.get_account(client)? {
+            Some(account) => {
+                output_accounts.insert(client, account);
+            }
+            None => {
+                // If the account doesn't exist, ignore it
+                eprintln!("Account {} doesn't exist but should", client);
+            }
+        }
+    }
+    dump_csv(&output_accounts, &mut csv_writer)?;
     Ok(())
 }
 
@@ -459,10 +879,16 @@ fn main() -> Result<()> {
     let mut csv_reader = ReaderBuilder::new()
         .has_headers(true)
         .trim(Trim::All)
+        .flexible(true)
         .from_path(path)?;
-    let one_pass = environment_check(path)?;
-
-    run(one_pass, &mut csv_reader)?;
+    if environment_check(path)? {
+        let mut store = MemStore::new();
+        run(&mut store, &mut csv_reader, std::io::stdout())?;
+    } else {
+        let sled_db = sled::Config::new().temporary(true).path("sled.db").open()?;
+        let mut store = SledStore::new(&sled_db)?;
+        run(&mut store, &mut csv_reader, std::io::stdout())?;
+    }
 
     Ok(())
 }